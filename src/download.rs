@@ -1,79 +1,161 @@
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
 
 use log::debug;
 
 use anyhow::{anyhow, Context, Error, Result};
 
+use md5::Context as Md5Context;
+
 use regex::{Match, Regex};
 
 use reqwest::blocking::{Client, Response};
-use reqwest::header::{ACCEPT_RANGES, RANGE};
+use reqwest::header::{ACCEPT_RANGES, CONTENT_ENCODING, RANGE};
 
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
+use sha2::{Digest, Sha256};
+
+// Algorithm used by the update feed to advertise the expected digest of a downloaded file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha256,
+}
+
+// Expected digest for a file, as advertised by the update feed
+#[derive(Debug, Clone)]
+pub struct ExpectedChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: String,
+}
+
 // Could not find a suitable crate to download a file that supports for resume
 pub fn download_file(
     client: &Client,
     url: &str,
     multi_progress: &MultiProgress,
     try_to_resume: bool,
+    output_dir: &Path,
+    expected_size: Option<u64>,
+    expected_checksum: Option<ExpectedChecksum>,
 ) -> Result<String, Error> {
+    fs::create_dir_all(output_dir)
+        .with_context(|| format!("Failed to create output directory {}", output_dir.display()))?;
+
     let mut resume_position: u64 = 0; // Greater than zero means we will resume download
-    let mut head_content_length: u64 = 0;
+    let mut probed_content_length: u64 = 0;
+    let mut probed_filename: Option<String> = None;
 
     if try_to_resume {
-        // Issuing a HEAD request to retrieve download name and size
+        // Built with compression negotiation disabled: once a client decodes a gzip/br/deflate
+        // body it strips Content-Encoding (and rewrites Content-Length), so checking those
+        // headers on a response from `client` would always read as "identity" even when the
+        // server actually compressed the transfer. A client built the same way is used below for
+        // the resumed GET itself, so identity is guaranteed for that request too rather than
+        // merely inferred from this probe request.
+        let identity_client = build_identity_client()?;
+
         debug!("Sending request HEAD {}", url);
-        let head_response = client.get(url).send()?;
-        debug!("Received response {:?}", head_response);
+        let probe_response = identity_client.get(url).send()?;
+        debug!("Received response {:?}", probe_response);
 
-        // Parse target filename from response
-        let head_filename = String::from(parse_filename(&head_response)?);
+        // Parse target filename from response, joined onto the requested output directory
+        let filename = join_output_dir(output_dir, parse_filename(&probe_response)?);
 
-        if !head_response.headers().contains_key(ACCEPT_RANGES) {
+        if fs::metadata(&filename).is_ok() {
+            // A final, non-partial file already on disk is always considered complete:
+            // partial downloads only ever live under the `.partial` name.
+            println!("Skipping download of file {}, already completed", filename);
+            return Ok(filename);
+        }
+
+        if !probe_response.headers().contains_key(ACCEPT_RANGES) {
             debug!("Server does support range header");
+        } else if !is_identity_encoded(&probe_response) {
+            // Byte offsets only line up with the file on disk when the server sends the
+            // identity encoding; resuming against a gzip/deflate/brotli stream would corrupt it.
+            debug!(
+                "Server applies a content-encoding, range-resume is not meaningful, downloading from scratch"
+            );
         } else {
-            let file_metadata = fs::metadata(&head_filename);
-            if file_metadata.is_ok() {
-                resume_position = file_metadata.ok().unwrap().len();
+            let partial_filename = partial_filename(&filename);
+            if let Ok(file_metadata) = fs::metadata(&partial_filename) {
+                let partial_size = file_metadata.len();
+                let content_length = probe_response.content_length().unwrap_or(0);
                 debug!(
-                    "File {} exists with size: {}",
-                    head_filename, resume_position
+                    "Partial file {} exists with size: {}",
+                    partial_filename, partial_size
                 );
 
-                head_content_length = head_response.content_length().unwrap_or(0);
-                if head_content_length == resume_position {
-                    println!(
-                        "Skipping download of file {}, already completed",
-                        head_filename
+                if content_length > 0 && partial_size == content_length {
+                    // The previous run likely crashed after the transfer finished but before
+                    // verification/rename (e.g. power loss). Re-validate in place instead of
+                    // issuing a `Range: bytes=N-` request for zero remaining bytes, whose
+                    // behavior (416 vs. re-sending the whole body) is server-dependent.
+                    debug!(
+                        "Partial file {} already has the full expected size, verifying without a new request",
+                        partial_filename
+                    );
+                    if verify_download(&partial_filename, expected_size, expected_checksum.as_ref())
+                        .is_ok()
+                    {
+                        fs::rename(&partial_filename, &filename).with_context(|| {
+                            format!(
+                                "Failed to move validated download {} to {}",
+                                partial_filename, filename
+                            )
+                        })?;
+                        return Ok(filename);
+                    }
+
+                    debug!(
+                        "Partial file {} failed verification, restarting download from scratch",
+                        partial_filename
                     );
-                    return Ok(head_filename);
+                    mark_as_invalid(&partial_filename)?;
+                } else {
+                    resume_position = partial_size;
+                    probed_content_length = content_length;
                 }
             }
         }
+
+        probed_filename = Some(filename);
     }
 
-    let mut request = client.get(url);
-    if resume_position > 0 {
+    let mut request = if resume_position > 0 {
+        // Use a freshly built no-compression client: decoding a compressed *partial* byte range would
+        // silently produce garbage, not just a wrong length, so identity must be guaranteed
+        // for the actual resumed GET, not just inferred from the earlier probe.
         debug!(
             "Adding range header to resume download: bytes={}-",
             resume_position
         );
-        request = request.header(RANGE, format!("bytes={}-", resume_position));
-    }
+        build_identity_client()?
+            .get(url)
+            .header(RANGE, format!("bytes={}-", resume_position))
+    } else {
+        client.get(url)
+    };
 
     debug!("Sending request GET {}", url);
     let mut response = request.send()?;
     debug!("Received response {:?}", response);
 
-    // Parse target filename from response
-    let filename = String::from(parse_filename(&response)?);
+    // Parse target filename from response, re-using the one learned from the probe request if any
+    let filename = match probed_filename {
+        Some(filename) => filename,
+        None => join_output_dir(output_dir, parse_filename(&response)?),
+    };
+    let partial_filename = partial_filename(&filename);
 
     let remaining_content_length = response.content_length().unwrap_or(0);
     let total_content_length = if resume_position > 0 {
-        head_content_length // content length retrieved on HEAD request in case of download resume
+        probed_content_length // content length retrieved on the probe request in case of download resume
     } else {
         remaining_content_length
     };
@@ -88,15 +170,15 @@ pub fn download_file(
     progress_bar.set_message(filename.to_string());
 
     let file = if resume_position == 0 {
-        debug!("Opening {} in create mode", filename);
-        File::create(filename.clone())
-            .with_context(|| format!("Failed to create file {}", filename))?
+        debug!("Opening {} in create mode", partial_filename);
+        File::create(partial_filename.clone())
+            .with_context(|| format!("Failed to create file {}", partial_filename))?
     } else {
-        debug!("Opening {} in append mode for resume", filename);
+        debug!("Opening {} in append mode for resume", partial_filename);
         OpenOptions::new()
             .append(true)
-            .open(filename.clone())
-            .with_context(|| format!("Failed to open file {} in append mode", filename))?
+            .open(partial_filename.clone())
+            .with_context(|| format!("Failed to open file {} in append mode", partial_filename))?
     };
 
     let mut buffer = [0u8; 4096];
@@ -108,23 +190,215 @@ pub fn download_file(
             .with_context(|| format!("Error reading from response body"))?;
         if count == 0 {
             // End of file.
-            //TODO we may want to check we actually got all expected bytes, or maybe
-            // reqwest ensures it?
             break;
         }
         progress_bar.inc(count as u64);
         file_writer
             .write_all(&buffer[..count])
-            .with_context(|| format!("Error writing to file {}", filename))?;
+            .with_context(|| format!("Error writing to file {}", partial_filename))?;
     }
     file_writer
         .flush()
-        .with_context(|| format!("Error flushing file {}", filename))?;
+        .with_context(|| format!("Error flushing file {}", partial_filename))?;
 
     progress_bar.finish();
+
+    if let Err(error) = verify_download(&partial_filename, expected_size, expected_checksum.as_ref())
+    {
+        mark_as_invalid(&partial_filename)?;
+        return Err(error);
+    }
+
+    // Only now that size and checksum are validated does the file become the final, resumable-as-complete artifact
+    fs::rename(&partial_filename, &filename).with_context(|| {
+        format!(
+            "Failed to move validated download {} to {}",
+            partial_filename, filename
+        )
+    })?;
+
     Ok(filename)
 }
 
+// Name under which an in-progress download is kept until it is validated and renamed to `filename`
+fn partial_filename(filename: &str) -> String {
+    format!("{}.partial", filename)
+}
+
+// Whether `path` is a `.partial` download or a `.partial.invalid` one left behind by
+// `mark_as_invalid` -- the only two suffixes those functions ever produce. A suffix check (as
+// opposed to a substring one) avoids deleting a finished download whose own name happens to
+// contain ".partial" somewhere in the middle.
+fn is_partial_download(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.ends_with(".partial") || name.ends_with(".partial.invalid"))
+        .unwrap_or(false)
+}
+
+// Remove `.partial` files older than `max_age` from the output directory. Aborted runs leave these
+// behind, and resuming against one whose remote file has since changed would produce a corrupt
+// download, so they're pruned before a new session starts.
+pub fn clean_stale_partial_downloads(dir: &Path, max_age: Duration) -> Result<(), Error> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    for entry in
+        fs::read_dir(dir).with_context(|| format!("Failed to read directory {}", dir.display()))?
+    {
+        let entry = entry.with_context(|| format!("Failed to read entry in {}", dir.display()))?;
+        let path = entry.path();
+
+        // Matches both a plain `<name>.partial` and a `<name>.partial.invalid` left behind by
+        // `mark_as_invalid` after a failed integrity check.
+        if !is_partial_download(&path) {
+            continue;
+        }
+
+        let metadata = entry
+            .metadata()
+            .with_context(|| format!("Failed to stat {}", path.display()))?;
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+
+        if age.map(|age| age > max_age).unwrap_or(false) {
+            debug!("Removing stale partial download {}", path.display());
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove stale file {}", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Join a filename (parsed from content-disposition or the URL) onto the chosen output directory.
+// The content-disposition parser accepts any non-whitespace token, including "../" traversal, so
+// only the final path component is kept -- otherwise a malicious or buggy server response could
+// escape the configured --output-dir entirely.
+fn join_output_dir(output_dir: &Path, filename: &str) -> String {
+    let sanitized = Path::new(filename)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| filename.to_string());
+    output_dir.join(sanitized).to_string_lossy().into_owned()
+}
+
+// Build a client that never negotiates compression. Used for both the resume-eligibility probe
+// and the resumed GET itself, so a server that would compress the response can't turn an
+// inferred "identity" into silently corrupted appended bytes.
+fn build_identity_client() -> Result<Client, Error> {
+    Client::builder()
+        .no_gzip()
+        .no_brotli()
+        .no_deflate()
+        .build()
+        .with_context(|| format!("Failed to create HTTP client without compression"))
+}
+
+// Whether the response is not content-encoded (or explicitly identity), meaning byte offsets
+// reported by the server correspond 1:1 to bytes written to disk and range-resume is safe.
+fn is_identity_encoded(response: &Response) -> bool {
+    match response.headers().get(CONTENT_ENCODING) {
+        None => true,
+        Some(encoding) => encoding
+            .to_str()
+            .map(|value| value.eq_ignore_ascii_case("identity"))
+            .unwrap_or(false),
+    }
+}
+
+// Check that a completed download matches the size and checksum advertised by the update feed,
+// so a truncated or corrupted transfer is never handed off as a valid firmware image.
+fn verify_download(
+    filename: &str,
+    expected_size: Option<u64>,
+    expected_checksum: Option<&ExpectedChecksum>,
+) -> Result<(), Error> {
+    if let Some(expected_size) = expected_size {
+        let actual_size = fs::metadata(filename)
+            .with_context(|| format!("Failed to stat downloaded file {}", filename))?
+            .len();
+        if actual_size != expected_size {
+            return Err(anyhow!(
+                "Downloaded file {} has size {} but expected {}",
+                filename,
+                actual_size,
+                expected_size
+            ));
+        }
+    }
+
+    if let Some(expected_checksum) = expected_checksum {
+        let digest = compute_checksum(filename, expected_checksum.algorithm)?;
+        if !digest.eq_ignore_ascii_case(&expected_checksum.digest) {
+            return Err(anyhow!(
+                "Checksum mismatch for file {}: expected {}, computed {}",
+                filename,
+                expected_checksum.digest,
+                digest
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Compute the hex digest of a file on disk using the given algorithm
+fn compute_checksum(filename: &str, algorithm: ChecksumAlgorithm) -> Result<String, Error> {
+    let mut file = File::open(filename)
+        .with_context(|| format!("Failed to open file {} for checksum verification", filename))?;
+    let mut buffer = [0u8; 8192];
+
+    match algorithm {
+        ChecksumAlgorithm::Md5 => {
+            let mut hasher = Md5Context::new();
+            loop {
+                let count = file
+                    .read(&mut buffer)
+                    .with_context(|| format!("Error reading file {} for checksum", filename))?;
+                if count == 0 {
+                    break;
+                }
+                hasher.consume(&buffer[..count]);
+            }
+            Ok(format!("{:x}", hasher.compute()))
+        }
+        ChecksumAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            loop {
+                let count = file
+                    .read(&mut buffer)
+                    .with_context(|| format!("Error reading file {} for checksum", filename))?;
+                if count == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..count]);
+            }
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+    }
+}
+
+// Rename a file that failed integrity verification so it can't be mistaken for a valid,
+// complete download on a later run.
+fn mark_as_invalid(filename: &str) -> Result<(), Error> {
+    let invalid_filename = format!("{}.invalid", filename);
+    fs::rename(filename, &invalid_filename).with_context(|| {
+        format!(
+            "Failed to mark corrupt file {} as {}",
+            filename, invalid_filename
+        )
+    })?;
+    debug!(
+        "Marked {} as invalid after failed integrity check: {}",
+        filename, invalid_filename
+    );
+    Ok(())
+}
+
 // Parse the name of the file to download from the response
 fn parse_filename(response: &Response) -> Result<&str, Error> {
     // Try to parse content-disposition header for filename
@@ -1,4 +1,5 @@
 use std::path::Path;
+use std::time::Duration;
 use std::vec::Vec;
 
 use anyhow::{anyhow, Context, Error, Result};
@@ -46,13 +47,61 @@ fn main() -> Result<(), Error> {
             .required(false)
             .long("map")
             .takes_value(true))
+        .arg(Arg::with_name("output-dir")
+            .help("Directory where downloaded update files are written. Defaults to the current directory")
+            .required(false)
+            .short("o")
+            .long("output-dir")
+            .takes_value(true))
+        .arg(Arg::with_name("clean")
+            .help("Remove stale .partial downloads from the output directory before checking for updates")
+            .required(false)
+            .long("clean"))
+        .arg(Arg::with_name("clean-max-age-days")
+            .help("Age in days after which a .partial download is considered stale and removed with --clean")
+            .required(false)
+            .long("clean-max-age-days")
+            .takes_value(true)
+            .default_value("7"))
+        .arg(Arg::with_name("yes")
+            .help("Auto-confirm every download/extraction prompt, for unattended runs")
+            .required(false)
+            .long("yes")
+            .short("y"))
+        .arg(Arg::with_name("download-only")
+            .help("Stop after downloading update(s), skipping the USB-extraction stage entirely")
+            .required(false)
+            .long("download-only"))
+        .arg(Arg::with_name("dest")
+            .help("Destination to extract update(s) to, bypassing the interactive prompt (root of an EMPTY USB device formatted as FAT32)")
+            .required(false)
+            .long("dest")
+            .takes_value(true))
         .get_matches();
 
     let vin = matches.value_of("VIN").expect("VIN is missing");
     let map = matches.value_of("map");
+    let output_dir = Path::new(matches.value_of("output-dir").unwrap_or("."));
+    let auto_yes = matches.is_present("yes");
+    let download_only = matches.is_present("download-only");
+    let dest_arg = matches.value_of("dest");
+
+    if matches.is_present("clean") {
+        let max_age_days: u64 = matches
+            .value_of("clean-max-age-days")
+            .expect("clean-max-age-days has a default value")
+            .parse()
+            .with_context(|| format!("Failed to parse --clean-max-age-days"))?;
+        download::clean_stale_partial_downloads(output_dir, Duration::from_secs(max_age_days * 24 * 60 * 60))
+            .with_context(|| format!("Failed to clean stale partial downloads"))?;
+    }
 
-    // TODO investigate compression such as gzip for faster download
+    // Negotiate transparent compression so the update-availability query and downloads
+    // come down smaller and faster on slow connections.
     let client = Client::builder()
+        .gzip(true)
+        .brotli(true)
+        .deflate(true)
         .build()
         .with_context(|| format!("Failed to create HTTP client"))?;
 
@@ -70,7 +119,7 @@ fn main() -> Result<(), Error> {
             // A empty update can be sent by the server when there are no available update
             if !update.update_id.is_empty() {
                 psa::print(&software, update);
-                if confirm("Download update?")? {
+                if confirm("Download update?", auto_yes)? {
                     selected_updates.push(update.clone());
                 }
             }
@@ -87,7 +136,7 @@ fn main() -> Result<(), Error> {
     let downloaded_updates: Result<Vec<psa::DownloadedUpdate>, _> = selected_updates
         .par_iter()
         .map(|update| {
-            psa::download_update(&client, update, &multi_progress)
+            psa::download_update(&client, update, &multi_progress, output_dir)
                 .with_context(|| format!("Failed to download update"))
         })
         .collect();
@@ -97,8 +146,13 @@ fn main() -> Result<(), Error> {
         Err(error) => return Err(error),
     };
 
+    if download_only {
+        return Ok(());
+    }
+
     if !confirm(
         "To proceed to extraction of update(s), please insert an empty USB disk formatted as FAT32. Continue?",
+        auto_yes,
     )? {
         return Ok(());
     }
@@ -110,7 +164,7 @@ fn main() -> Result<(), Error> {
     // TODO check destination available space.
     psa::print_disks(&sys);
 
-    let destination = prompt("Location where to extract the update files (IMPORTANT: Should be the root of an EMPTY USB device formatted as FAT32): ")?;
+    let destination = prompt("Location where to extract the update files (IMPORTANT: Should be the root of an EMPTY USB device formatted as FAT32): ", dest_arg)?;
     if destination.is_empty() {
         println!("No location, skipping extraction");
     } else {
@@ -131,10 +185,16 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
-fn confirm(message: &str) -> Result<bool, Error> {
+fn confirm(message: &str, auto_yes: bool) -> Result<bool, Error> {
+    if auto_yes {
+        return Ok(true);
+    }
     Ok(Confirm::new().with_prompt(message).interact()?)
 }
 
-fn prompt(message: &str) -> Result<String, Error> {
+fn prompt(message: &str, preset: Option<&str>) -> Result<String, Error> {
+    if let Some(preset) = preset {
+        return Ok(preset.to_string());
+    }
     Ok(Input::new().with_prompt(message).interact_text()?)
 }
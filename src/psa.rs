@@ -0,0 +1,151 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Error, Result};
+
+use log::debug;
+
+use reqwest::blocking::Client;
+
+use serde::Deserialize;
+
+use indicatif::MultiProgress;
+
+use sysinfo::{DiskExt, System, SystemExt};
+
+use zip::ZipArchive;
+
+use crate::download;
+use crate::download::{ChecksumAlgorithm, ExpectedChecksum};
+
+const UPDATE_AVAILABILITY_URL: &str =
+    "https://api.groupe-psa.com/applications/majesticf/v1/getAvailableUpdate";
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateResponse {
+    pub software: Option<Vec<Software>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Software {
+    pub software_type: String,
+    pub update: Vec<SoftwareUpdate>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SoftwareUpdate {
+    pub update_id: String,
+    pub update_version: String,
+    pub url: String,
+    // Advertised size of the downloadable file, in bytes, as reported by the update feed
+    pub size: u64,
+    // Hex MD5 digest of the downloadable file, when advertised by the update feed
+    pub md5: Option<String>,
+}
+
+pub struct DownloadedUpdate {
+    pub update: SoftwareUpdate,
+    pub file_path: String,
+}
+
+pub fn request_available_updates(
+    client: &Client,
+    vin: &str,
+    map: Option<&str>,
+) -> Result<UpdateResponse, Error> {
+    let mut request = client.get(UPDATE_AVAILABILITY_URL).query(&[("vin", vin)]);
+    if let Some(map) = map {
+        request = request.query(&[("map", map)]);
+    }
+
+    debug!("Sending request GET {}", UPDATE_AVAILABILITY_URL);
+    let response = request
+        .send()
+        .with_context(|| format!("Failed to request available updates"))?;
+    debug!("Received response {:?}", response);
+
+    response
+        .json::<UpdateResponse>()
+        .with_context(|| format!("Failed to parse available updates response"))
+}
+
+pub fn print(software: &Software, update: &SoftwareUpdate) {
+    println!(
+        "{} - update {} version {} ({} bytes) available",
+        software.software_type, update.update_id, update.update_version, update.size
+    );
+}
+
+pub fn download_update(
+    client: &Client,
+    update: &SoftwareUpdate,
+    multi_progress: &MultiProgress,
+    output_dir: &Path,
+) -> Result<DownloadedUpdate, Error> {
+    let expected_checksum = update.md5.as_ref().map(|digest| ExpectedChecksum {
+        algorithm: ChecksumAlgorithm::Md5,
+        digest: digest.clone(),
+    });
+
+    let file_path = download::download_file(
+        client,
+        &update.url,
+        multi_progress,
+        true,
+        output_dir,
+        Some(update.size),
+        expected_checksum,
+    )?;
+
+    Ok(DownloadedUpdate {
+        update: update.clone(),
+        file_path,
+    })
+}
+
+pub fn print_disks(system: &System) {
+    for disk in system.disks() {
+        println!(
+            "{} ({})",
+            disk.mount_point().to_string_lossy(),
+            disk.name().to_string_lossy()
+        );
+    }
+}
+
+pub fn extract_update(downloaded: &DownloadedUpdate, destination: &Path) -> Result<(), Error> {
+    let file = File::open(&downloaded.file_path)
+        .with_context(|| format!("Failed to open downloaded update {}", downloaded.file_path))?;
+
+    let mut archive = ZipArchive::new(file)
+        .with_context(|| format!("Failed to read update archive {}", downloaded.file_path))?;
+
+    for index in 0..archive.len() {
+        let mut entry = archive
+            .by_index(index)
+            .with_context(|| format!("Failed to read entry {} of {}", index, downloaded.file_path))?;
+
+        let entry_path = match entry.enclosed_name() {
+            Some(path) => destination.join(path),
+            None => continue,
+        };
+
+        if entry.is_dir() {
+            fs::create_dir_all(&entry_path)
+                .with_context(|| format!("Failed to create directory {}", entry_path.display()))?;
+        } else {
+            if let Some(parent) = entry_path.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+            }
+            let mut out_file = File::create(&entry_path)
+                .with_context(|| format!("Failed to create file {}", entry_path.display()))?;
+            io::copy(&mut entry, &mut out_file)
+                .with_context(|| format!("Failed to extract file {}", entry_path.display()))?;
+        }
+    }
+
+    Ok(())
+}